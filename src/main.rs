@@ -1,13 +1,16 @@
-use image::codecs::png::PngEncoder;
-use image::{load_from_memory, ImageBuffer, Rgba};
+use image::codecs::gif::{GifDecoder, GifEncoder, Repeat};
+use image::{load_from_memory, AnimationDecoder, ImageBuffer, ImageFormat, Rgba};
 
 use anyhow::anyhow;
 use anyhow::Result;
 use clap::{arg, command, Parser};
 use image::ImageReader;
-use std::io::Read;
+use rayon::prelude::*;
+use std::io::{Cursor, Read, Write};
 use std::path::PathBuf;
 
+type RgbaImage = ImageBuffer<Rgba<u8>, Vec<u8>>;
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct AsdfArgs {
@@ -23,93 +26,330 @@ struct AsdfArgs {
     #[arg(short = 'd', long = "direction", value_name = "DIRECTION")]
     direction: String,
 
-    /// Sorting mode
-    #[arg(short = 'm', long = "mode", value_name = "MODE")]
-    mode: Mode,
+    /// Sort key used to decide which pixels are sortable and in what order
+    #[arg(short = 'k', long = "key", value_name = "KEY", default_value = "brightness")]
+    key: SortKey,
+
+    /// Lower bound of the sortable interval, normalized to 0.0-1.0
+    #[arg(long = "lower", value_name = "LOWER", default_value_t = 0.0)]
+    lower: f32,
+
+    /// Upper bound of the sortable interval, normalized to 0.0-1.0
+    #[arg(long = "upper", value_name = "UPPER", default_value_t = 1.0)]
+    upper: f32,
+
+    /// Sort runs in descending order of the chosen key instead of ascending
+    #[arg(short = 'r', long = "reverse")]
+    reverse: bool,
+
+    /// Number of worker threads to sort with (0 = all cores, 1 = serial)
+    #[arg(short = 'j', long = "threads", value_name = "THREADS", default_value_t = 0)]
+    threads: usize,
+
+    /// Sort along an arbitrary angle in degrees instead of strict h/v runs
+    #[arg(
+        short = 'a',
+        long = "angle",
+        value_name = "DEGREES",
+        allow_hyphen_values = true,
+        default_value_t = 0.0
+    )]
+    angle: f64,
+
+    /// Break runs at Sobel edges instead of the key/interval test
+    #[arg(long = "edges", value_name = "THRESHOLD")]
+    edges: Option<f32>,
+
+    /// Output image format; inferred from the output path's extension if omitted.
+    /// Multi-frame GIF input is always written as an animated GIF.
+    #[arg(short = 'f', long = "format", value_name = "FORMAT")]
+    format: Option<OutputFormat>,
+
+    /// Mask image; white pixels permit sorting, black pixels forbid it
+    #[arg(long = "mask", value_name = "MASK")]
+    mask: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum SortKey {
+    Luma,
+    Brightness,
+    Saturation,
+    Hue,
+}
+
+impl std::str::FromStr for SortKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "luma" => Ok(SortKey::Luma),
+            "brightness" => Ok(SortKey::Brightness),
+            "saturation" => Ok(SortKey::Saturation),
+            "hue" => Ok(SortKey::Hue),
+            _ => Err(anyhow!(
+                "Invalid key. Must be one of: luma, brightness, saturation, hue"
+            )),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
-enum Mode {
-    White,
-    Black,
-    Bright,
-    Dark,
+enum OutputFormat {
+    Png,
+    Jpeg,
+    Tiff,
+    WebP,
+    Bmp,
+    Gif,
 }
 
-impl std::str::FromStr for Mode {
+impl std::str::FromStr for OutputFormat {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self> {
         match s.to_lowercase().as_str() {
-            "white" => Ok(Mode::White),
-            "black" => Ok(Mode::Black),
-            "bright" => Ok(Mode::Bright),
-            "dark" => Ok(Mode::Dark),
+            "png" => Ok(OutputFormat::Png),
+            "jpeg" | "jpg" => Ok(OutputFormat::Jpeg),
+            "tiff" => Ok(OutputFormat::Tiff),
+            "webp" => Ok(OutputFormat::WebP),
+            "bmp" => Ok(OutputFormat::Bmp),
+            "gif" => Ok(OutputFormat::Gif),
             _ => Err(anyhow!(
-                "Invalid mode. Must be one of: white, black, bright, dark"
+                "Invalid format. Must be one of: png, jpeg, tiff, webp, bmp, gif"
             )),
         }
     }
 }
 
+impl From<OutputFormat> for ImageFormat {
+    fn from(format: OutputFormat) -> Self {
+        match format {
+            OutputFormat::Png => ImageFormat::Png,
+            OutputFormat::Jpeg => ImageFormat::Jpeg,
+            OutputFormat::Tiff => ImageFormat::Tiff,
+            OutputFormat::WebP => ImageFormat::WebP,
+            OutputFormat::Bmp => ImageFormat::Bmp,
+            OutputFormat::Gif => ImageFormat::Gif,
+        }
+    }
+}
+
+/// The parameters that decide which pixels are sortable and how sorted runs
+/// are ordered, threaded through the row/column sweeps.
+#[derive(Debug, Clone, Copy)]
+struct SortSpec {
+    key: SortKey,
+    lower: f32,
+    upper: f32,
+    reverse: bool,
+    edges: Option<f32>,
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = AsdfArgs::parse();
 
-    // Read input
-    let image_data = if let Some(input_path) = &args.input {
-        ImageReader::open(input_path)?.decode()?
-    } else {
-        let stdin = std::io::stdin();
+    if args.threads != 1 {
+        // threads == 0 tells rayon to size the pool to the available cores.
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(args.threads)
+            .build_global()?;
+    }
 
+    // Read the raw bytes up front so a multi-frame GIF can be detected and
+    // decoded frame-by-frame before falling back to a single still image.
+    let input_bytes = if let Some(input_path) = &args.input {
+        std::fs::read(input_path)?
+    } else {
         let mut buffer = Vec::new();
-        stdin.lock().read_to_end(&mut buffer)?;
-        load_from_memory(buffer.as_slice())?
+        std::io::stdin().lock().read_to_end(&mut buffer)?;
+        buffer
     };
 
-    // Convert to rgba8
-    let mut img = image_data.to_rgba8();
+    let spec = SortSpec {
+        key: args.key,
+        lower: args.lower,
+        upper: args.upper,
+        reverse: args.reverse,
+        edges: args.edges,
+    };
 
-    // Process the image based on direction
-    match args.direction.as_str() {
-        "h" => {
-            for x in 0..img.width() {
-                process_column(&mut img, x, args.mode);
-            }
-            for y in 0..img.height() {
-                process_row(&mut img, y, args.mode);
-            }
+    if image::guess_format(&input_bytes)? == ImageFormat::Gif {
+        let frames = GifDecoder::new(Cursor::new(&input_bytes))?
+            .into_frames()
+            .collect_frames()?;
+
+        if frames.len() > 1 {
+            let (width, height) = {
+                let first = frames[0].buffer();
+                (first.width(), first.height())
+            };
+            let mask = load_mask(&args.mask, width, height)?;
+
+            let sorted_frames = frames
+                .into_iter()
+                .map(|frame| {
+                    let delay = frame.delay();
+                    let sorted = process_image(frame.into_buffer(), &args, &spec, mask.as_ref());
+                    image::Frame::from_parts(sorted, 0, 0, delay)
+                })
+                .collect();
+
+            write_gif(&args, sorted_frames, read_gif_loop_count(&input_bytes))?;
+            return Ok(());
         }
-        "v" => {
-            for y in 0..img.height() {
-                process_row(&mut img, y, args.mode);
+    }
+
+    let img = load_from_memory(&input_bytes)?.to_rgba8();
+    let mask = load_mask(&args.mask, img.width(), img.height())?;
+    let sorted = process_image(img, &args, &spec, mask.as_ref());
+    write_image(&args, &sorted)?;
+
+    Ok(())
+}
+
+/// Loads and resizes the mask image to match the working canvas, if one was
+/// given, so it can be indexed pixel-for-pixel.
+fn load_mask(
+    path: &Option<PathBuf>,
+    width: u32,
+    height: u32,
+) -> Result<Option<RgbaImage>, Box<dyn std::error::Error>> {
+    path.as_ref()
+        .map(|path| -> Result<RgbaImage, Box<dyn std::error::Error>> {
+            let mask_img = ImageReader::open(path)?.decode()?.to_rgba8();
+            Ok(image::imageops::resize(
+                &mask_img,
+                width,
+                height,
+                image::imageops::FilterType::Triangle,
+            ))
+        })
+        .transpose()
+}
+
+/// Sorts a single image (or GIF frame) according to `args`, either along the
+/// two-phase h/v sweep or, when an angle is given, by rotating into it.
+fn process_image(
+    mut img: RgbaImage,
+    args: &AsdfArgs,
+    spec: &SortSpec,
+    mask: Option<&RgbaImage>,
+) -> RgbaImage {
+    if args.angle != 0.0 {
+        let (width, height) = (img.width(), img.height());
+
+        // Rotate so the desired sort direction lines up with the rows, sort,
+        // then rotate back and crop away the padding the rotation added.
+        let mut rotated = rotate_image(&img, -args.angle);
+        let rotated_mask = mask.map(|m| rotate_image(m, -args.angle));
+        let sortable = compute_sortable_mask(&rotated, spec, rotated_mask.as_ref());
+        sort_rows(&mut rotated, &sortable, spec, args.threads);
+        let unrotated = rotate_image(&rotated, args.angle);
+        crop_center(&unrotated, width, height)
+    } else {
+        // Computed once from the pristine image so the edge map reflects
+        // original object boundaries in both phases, rather than the
+        // streaks the first phase leaves behind.
+        let sortable = compute_sortable_mask(&img, spec, mask);
+        match args.direction.as_str() {
+            "h" => {
+                sort_columns(&mut img, &sortable, spec, args.threads);
+                sort_rows(&mut img, &sortable, spec, args.threads);
             }
-            for x in 0..img.width() {
-                process_column(&mut img, x, args.mode);
+            "v" => {
+                sort_rows(&mut img, &sortable, spec, args.threads);
+                sort_columns(&mut img, &sortable, spec, args.threads);
             }
+            _ => unreachable!(),
         }
-        _ => unreachable!(),
+        img
     }
+}
 
-    // Write output
+fn write_image(args: &AsdfArgs, img: &RgbaImage) -> Result<(), Box<dyn std::error::Error>> {
+    let format = match (args.format, &args.output) {
+        (Some(format), _) => format.into(),
+        (None, Some(output_path)) => ImageFormat::from_path(output_path)?,
+        (None, None) => ImageFormat::Png,
+    };
+
+    if format == ImageFormat::Jpeg {
+        // JPEG has no alpha channel; flatten onto an opaque RGB buffer first.
+        let rgb = image::DynamicImage::ImageRgba8(img.clone()).to_rgb8();
+        return write_encoded(args, &rgb, format);
+    }
+
+    write_encoded(args, img, format)
+}
+
+fn write_encoded<P, Container>(
+    args: &AsdfArgs,
+    img: &ImageBuffer<P, Container>,
+    format: ImageFormat,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    P: image::Pixel + image::PixelWithColorType,
+    [P::Subpixel]: image::EncodableLayout,
+    Container: std::ops::Deref<Target = [P::Subpixel]>,
+{
     if let Some(output_path) = &args.output {
-        img.save(output_path)?;
+        img.save_with_format(output_path, format)?;
     } else {
-        let stdout = std::io::stdout();
-        let encoder = PngEncoder::new(stdout);
-        img.write_with_encoder(encoder)?;
+        // Stdout isn't seekable, so encode into an in-memory buffer first.
+        let mut buffer = Vec::new();
+        img.write_to(&mut Cursor::new(&mut buffer), format)?;
+        std::io::stdout().write_all(&buffer)?;
     }
+    Ok(())
+}
 
+fn write_gif(
+    args: &AsdfArgs,
+    frames: Vec<image::Frame>,
+    repeat: Repeat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(output_path) = &args.output {
+        let mut encoder = GifEncoder::new(std::fs::File::create(output_path)?);
+        encoder.set_repeat(repeat)?;
+        encoder.encode_frames(frames)?;
+    } else {
+        let mut encoder = GifEncoder::new(std::io::stdout().lock());
+        encoder.set_repeat(repeat)?;
+        encoder.encode_frames(frames)?;
+    }
     Ok(())
 }
 
-const WHITE_THRESHOLD: u32 = 0x123456u32;
-const BLACK_THRESHOLD: u32 = 0x345678u32;
-const BRIGHT_THRESHOLD: u8 = 127;
-const DARK_THRESHOLD: u8 = 223;
+/// Reads the source GIF's NETSCAPE2.0 application extension to recover its
+/// loop count. `image`'s `GifDecoder` doesn't surface this, so the raw bytes
+/// are scanned for the extension directly. Per the GIF89a spec, a GIF with
+/// no such extension plays once; a loop count of `0` within it means loop
+/// forever.
+fn read_gif_loop_count(bytes: &[u8]) -> Repeat {
+    const SIGNATURE: &[u8] = b"NETSCAPE2.0";
 
-fn pixel_value(pixel: &Rgba<u8>) -> u32 {
-    let [r, g, b, _] = pixel.0;
-    (r as u32) * (g as u32) * (b as u32)
+    let Some(signature_pos) = bytes
+        .windows(SIGNATURE.len())
+        .position(|window| window == SIGNATURE)
+    else {
+        return Repeat::Finite(1);
+    };
+
+    // Signature is followed by a sub-block: its length byte (always 0x03),
+    // then 3 data bytes: a sub-block ID (0x01) and the little-endian loop count.
+    let sub_block = &bytes[signature_pos + SIGNATURE.len()..];
+    match sub_block {
+        [0x03, 0x01, lo, hi, ..] => {
+            let count = u16::from_le_bytes([*lo, *hi]);
+            if count == 0 {
+                Repeat::Infinite
+            } else {
+                Repeat::Finite(count)
+            }
+        }
+        _ => Repeat::Finite(1),
+    }
 }
 
 fn brightness(pixel: &Rgba<u8>) -> u8 {
@@ -117,71 +357,343 @@ fn brightness(pixel: &Rgba<u8>) -> u8 {
     ((r as u16 + g as u16 + b as u16) / 3) as u8
 }
 
-fn should_sort(pixel: &Rgba<u8>, mode: Mode) -> bool {
-    match mode {
-        Mode::White => pixel_value(pixel) < WHITE_THRESHOLD,
-        Mode::Black => pixel_value(pixel) > BLACK_THRESHOLD,
-        Mode::Bright => brightness(pixel) > BRIGHT_THRESHOLD,
-        Mode::Dark => brightness(pixel) < DARK_THRESHOLD,
-    }
+/// Converts a pixel to HSL, returning `(hue_degrees, saturation, lightness)`
+/// with saturation and lightness normalized to `0.0..=1.0`.
+fn rgb_to_hsl(pixel: &Rgba<u8>) -> (f32, f32, f32) {
+    let [r, g, b, _] = pixel.0;
+    let r = r as f32 / 255.0;
+    let g = g as f32 / 255.0;
+    let b = b as f32 / 255.0;
+
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let delta = max - min;
+    let lightness = (max + min) / 2.0;
+
+    let saturation = if delta == 0.0 {
+        0.0
+    } else {
+        delta / (1.0 - (2.0 * lightness - 1.0).abs())
+    };
+
+    let hue = if delta == 0.0 {
+        0.0
+    } else if max == r {
+        60.0 * (((g - b) / delta).rem_euclid(6.0))
+    } else if max == g {
+        60.0 * (((b - r) / delta) + 2.0)
+    } else {
+        60.0 * (((r - g) / delta) + 4.0)
+    };
+
+    (hue, saturation, lightness)
 }
 
-fn process_row(img: &mut ImageBuffer<Rgba<u8>, Vec<u8>>, y: u32, mode: Mode) {
-    let mut x = 0;
-    while x < img.width() {
-        while x < img.width() && !should_sort(img.get_pixel(x, y), mode) {
-            x += 1;
+/// Evaluates a pixel's chosen sort key, normalized to `0.0..=1.0`.
+fn sort_key_value(pixel: &Rgba<u8>, key: SortKey) -> f32 {
+    match key {
+        SortKey::Luma => {
+            let [r, g, b, _] = pixel.0;
+            (0.2126 * r as f32 + 0.7152 * g as f32 + 0.0722 * b as f32) / 255.0
         }
-        let start = x;
-        while x < img.width() && should_sort(img.get_pixel(x, y), mode) {
-            x += 1;
+        SortKey::Brightness => brightness(pixel) as f32 / 255.0,
+        SortKey::Saturation => rgb_to_hsl(pixel).1,
+        SortKey::Hue => rgb_to_hsl(pixel).0 / 360.0,
+    }
+}
+
+fn in_key_interval(pixel: &Rgba<u8>, spec: &SortSpec) -> bool {
+    let value = sort_key_value(pixel, spec.key);
+    value >= spec.lower && value <= spec.upper
+}
+
+/// Computes the Sobel gradient magnitude of the luma channel at every pixel
+/// and marks it as an edge when the magnitude exceeds `threshold`.
+fn compute_edge_map(img: &RgbaImage, threshold: f32) -> Vec<bool> {
+    let width = img.width() as i64;
+    let height = img.height() as i64;
+
+    let luma_at = |x: i64, y: i64| -> f32 {
+        let x = x.clamp(0, width - 1) as u32;
+        let y = y.clamp(0, height - 1) as u32;
+        let [r, g, b, _] = img.get_pixel(x, y).0;
+        0.2126 * r as f32 + 0.7152 * g as f32 + 0.0722 * b as f32
+    };
+
+    let mut edges = vec![false; (width * height) as usize];
+    for y in 0..height {
+        for x in 0..width {
+            let gx = -luma_at(x - 1, y - 1) + luma_at(x + 1, y - 1) - 2.0 * luma_at(x - 1, y)
+                + 2.0 * luma_at(x + 1, y)
+                - luma_at(x - 1, y + 1)
+                + luma_at(x + 1, y + 1);
+            let gy = -luma_at(x - 1, y - 1) - 2.0 * luma_at(x, y - 1) - luma_at(x + 1, y - 1)
+                + luma_at(x - 1, y + 1)
+                + 2.0 * luma_at(x, y + 1)
+                + luma_at(x + 1, y + 1);
+            let magnitude = (gx * gx + gy * gy).sqrt();
+            edges[(y * width + x) as usize] = magnitude > threshold;
         }
-        let end = x;
+    }
+    edges
+}
 
-        if start < end {
-            let mut segment: Vec<_> = (start..end).map(|x| *img.get_pixel(x, y)).collect();
-            segment.sort_by(|a, b| {
-                let av = pixel_value(a);
-                let bv = pixel_value(b);
-                match mode {
-                    Mode::White | Mode::Bright => av.cmp(&bv),
-                    Mode::Black | Mode::Dark => bv.cmp(&av),
-                }
-            });
+/// Computes, for every pixel, whether it belongs in a sortable run. Fully
+/// transparent pixels (rotation padding) are never sortable; otherwise the
+/// image is split into runs either by the Sobel edge map or by the key
+/// interval test, depending on `spec`. A mask, if given, further restricts
+/// sortability to its light (>50% luminance) pixels.
+fn compute_sortable_mask(img: &RgbaImage, spec: &SortSpec, mask: Option<&RgbaImage>) -> Vec<bool> {
+    let width = img.width();
+    let height = img.height();
+    let edge_map = spec.edges.map(|threshold| compute_edge_map(img, threshold));
+
+    (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .map(|(x, y)| {
+            let pixel = img.get_pixel(x, y);
+            if pixel.0[3] == 0 {
+                return false;
+            }
+
+            let base_sortable = match &edge_map {
+                Some(edges) => !edges[(y * width + x) as usize],
+                None => in_key_interval(pixel, spec),
+            };
+            if !base_sortable {
+                return false;
+            }
 
-            for (i, pixel) in segment.into_iter().enumerate() {
-                img.put_pixel(start + i as u32, y, pixel);
+            match mask {
+                Some(mask_img) => sort_key_value(mask_img.get_pixel(x, y), SortKey::Luma) > 0.5,
+                None => true,
             }
+        })
+        .collect()
+}
+
+/// Rotates `img` by `degrees` about its center using nearest-neighbor
+/// sampling, expanding the canvas so no content is clipped. The newly
+/// exposed border is filled with fully transparent pixels.
+fn rotate_image(img: &RgbaImage, degrees: f64) -> RgbaImage {
+    let radians = degrees.to_radians();
+    let (sin, cos) = radians.sin_cos();
+    let (width, height) = (img.width() as f64, img.height() as f64);
+
+    let new_width = (width * cos.abs() + height * sin.abs()).ceil() as u32;
+    let new_height = (width * sin.abs() + height * cos.abs()).ceil() as u32;
+
+    let (cx, cy) = (width / 2.0, height / 2.0);
+    let (new_cx, new_cy) = (new_width as f64 / 2.0, new_height as f64 / 2.0);
+
+    let mut out = RgbaImage::new(new_width, new_height);
+    for y in 0..new_height {
+        for x in 0..new_width {
+            // Map the destination pixel back into source space with the
+            // inverse rotation to find what to sample.
+            let dx = x as f64 - new_cx;
+            let dy = y as f64 - new_cy;
+            let sx = dx * cos + dy * sin + cx;
+            let sy = -dx * sin + dy * cos + cy;
+
+            // Bounds-check the rounded sample, not the pre-round float: e.g.
+            // sx = 499.6 passes `sx < 500.0` but rounds to the out-of-bounds
+            // index 500.
+            let (sample_x, sample_y) = (sx.round(), sy.round());
+            let pixel = if sample_x >= 0.0 && sample_y >= 0.0 && sample_x < width && sample_y < height
+            {
+                *img.get_pixel(sample_x as u32, sample_y as u32)
+            } else {
+                Rgba([0, 0, 0, 0])
+            };
+            out.put_pixel(x, y, pixel);
         }
     }
+    out
 }
 
-fn process_column(img: &mut ImageBuffer<Rgba<u8>, Vec<u8>>, x: u32, mode: Mode) {
-    let mut y = 0;
-    while y < img.height() {
-        while y < img.height() && !should_sort(img.get_pixel(x, y), mode) {
-            y += 1;
+/// Crops `img` down to `width`x`height`, taken from its center.
+fn crop_center(img: &RgbaImage, width: u32, height: u32) -> RgbaImage {
+    let x0 = (img.width().saturating_sub(width)) / 2;
+    let y0 = (img.height().saturating_sub(height)) / 2;
+    image::imageops::crop_imm(img, x0, y0, width, height).to_image()
+}
+
+/// Sorts the sortable runs of a single row or column, given as a flat slice
+/// of pixels in their original order alongside a parallel slice marking
+/// which of those pixels are sortable.
+fn sort_line(line: &mut [Rgba<u8>], sortable: &[bool], spec: &SortSpec) {
+    let len = line.len();
+    let mut i = 0;
+    while i < len {
+        while i < len && !sortable[i] {
+            i += 1;
         }
-        let start = y;
-        while y < img.height() && should_sort(img.get_pixel(x, y), mode) {
-            y += 1;
+        let start = i;
+        while i < len && sortable[i] {
+            i += 1;
         }
-        let end = y;
+        let end = i;
 
         if start < end {
-            let mut segment: Vec<_> = (start..end).map(|y| *img.get_pixel(x, y)).collect();
-            segment.sort_by(|a, b| {
-                let av = pixel_value(a);
-                let bv = pixel_value(b);
-                match mode {
-                    Mode::White | Mode::Bright => av.cmp(&bv),
-                    Mode::Black | Mode::Dark => bv.cmp(&av),
+            line[start..end].sort_by(|a, b| {
+                let av = sort_key_value(a, spec.key);
+                let bv = sort_key_value(b, spec.key);
+                let ordering = av.partial_cmp(&bv).unwrap_or(std::cmp::Ordering::Equal);
+                if spec.reverse {
+                    ordering.reverse()
+                } else {
+                    ordering
                 }
             });
+        }
+    }
+}
+
+/// Sorts every row independently. Rows don't alias each other in memory, so
+/// with more than one thread they're sorted concurrently via `par_iter_mut`.
+/// `sortable` is the whole image's sortable mask, computed once up front by
+/// the caller so both phases see the same one.
+fn sort_rows(img: &mut RgbaImage, sortable: &[bool], spec: &SortSpec, threads: usize) {
+    let width = img.width();
+    let height = img.height();
+
+    let mut rows: Vec<(Vec<Rgba<u8>>, &[bool])> = (0..height)
+        .map(|y| {
+            let pixels = (0..width).map(|x| *img.get_pixel(x, y)).collect();
+            let start = (y * width) as usize;
+            (pixels, &sortable[start..start + width as usize])
+        })
+        .collect();
 
-            for (i, &mut pixel) in segment.iter_mut().enumerate() {
-                img.put_pixel(x, start + i as u32, pixel);
+    let sort_one = |(pixels, row_mask): &mut (Vec<Rgba<u8>>, &[bool])| {
+        sort_line(pixels, row_mask, spec)
+    };
+    if threads == 1 {
+        rows.iter_mut().for_each(sort_one);
+    } else {
+        rows.par_iter_mut().for_each(sort_one);
+    }
+
+    for (y, (row, _)) in rows.into_iter().enumerate() {
+        for (x, pixel) in row.into_iter().enumerate() {
+            img.put_pixel(x as u32, y as u32, pixel);
+        }
+    }
+}
+
+/// Sorts every column independently. Columns aren't contiguous in the
+/// underlying buffer, so each is gathered into its own indexable `Vec`
+/// before being handed to a worker thread. `sortable` is the whole image's
+/// sortable mask, computed once up front by the caller so both phases see
+/// the same one.
+fn sort_columns(img: &mut RgbaImage, sortable: &[bool], spec: &SortSpec, threads: usize) {
+    let width = img.width();
+    let height = img.height();
+
+    let mut columns: Vec<(Vec<Rgba<u8>>, Vec<bool>)> = (0..width)
+        .map(|x| {
+            let pixels = (0..height).map(|y| *img.get_pixel(x, y)).collect();
+            let column_mask = (0..height)
+                .map(|y| sortable[(y * width + x) as usize])
+                .collect();
+            (pixels, column_mask)
+        })
+        .collect();
+
+    let sort_one = |(pixels, column_mask): &mut (Vec<Rgba<u8>>, Vec<bool>)| {
+        sort_line(pixels, column_mask, spec)
+    };
+    if threads == 1 {
+        columns.iter_mut().for_each(sort_one);
+    } else {
+        columns.par_iter_mut().for_each(sort_one);
+    }
+
+    for (x, (column, _)) in columns.into_iter().enumerate() {
+        for (y, pixel) in column.into_iter().enumerate() {
+            img.put_pixel(x as u32, y as u32, pixel);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotate_image_stays_in_bounds_at_every_angle() {
+        // Regression test for ea1f3e7: a rounded sample coordinate landing
+        // exactly on the source image's edge must not panic `get_pixel`.
+        let img = RgbaImage::from_fn(5, 5, |x, y| Rgba([x as u8, y as u8, 0, 255]));
+        for degrees in 0..360 {
+            let rotated = rotate_image(&img, degrees as f64);
+            assert!(rotated.width() > 0 && rotated.height() > 0);
+        }
+    }
+
+    #[test]
+    fn rotate_image_by_zero_degrees_is_identity() {
+        let img = RgbaImage::from_fn(4, 3, |x, y| Rgba([x as u8 * 10, y as u8 * 10, 5, 255]));
+        let rotated = rotate_image(&img, 0.0);
+        assert_eq!(rotated.width(), img.width());
+        assert_eq!(rotated.height(), img.height());
+        assert_eq!(rotated, img);
+    }
+
+    #[test]
+    fn compute_edge_map_finds_a_vertical_gradient_boundary() {
+        // Left half black, right half white: a clean vertical edge between
+        // the two middle columns, which are both marked, and nowhere else.
+        let width = 10;
+        let height = 4;
+        let img = RgbaImage::from_fn(width, height, |x, _| {
+            if x < width / 2 {
+                Rgba([0, 0, 0, 255])
+            } else {
+                Rgba([255, 255, 255, 255])
             }
+        });
+
+        let edges = compute_edge_map(&img, 100.0);
+
+        for y in 0..height {
+            let left_col = (width / 2 - 1) as usize;
+            let right_col = (width / 2) as usize;
+            assert!(edges[(y as usize) * width as usize + left_col]);
+            assert!(edges[(y as usize) * width as usize + right_col]);
         }
+
+        // A flat region far from the boundary has no gradient to detect.
+        assert!(!edges[1]);
+    }
+
+    #[test]
+    fn compute_edge_map_is_all_false_on_a_flat_image() {
+        let img = RgbaImage::from_pixel(6, 6, Rgba([128, 128, 128, 255]));
+        let edges = compute_edge_map(&img, 1.0);
+        assert!(edges.iter().all(|&edge| !edge));
+    }
+
+    #[test]
+    fn read_gif_loop_count_parses_looping_extension() {
+        let mut bytes = b"GIF89a".to_vec();
+        bytes.extend_from_slice(b"NETSCAPE2.0");
+        bytes.extend_from_slice(&[0x03, 0x01, 0x00, 0x00, 0x00]);
+        assert_eq!(read_gif_loop_count(&bytes), Repeat::Infinite);
+    }
+
+    #[test]
+    fn read_gif_loop_count_parses_finite_loop_count() {
+        let mut bytes = b"GIF89a".to_vec();
+        bytes.extend_from_slice(b"NETSCAPE2.0");
+        bytes.extend_from_slice(&[0x03, 0x01, 0x05, 0x00, 0x00]);
+        assert_eq!(read_gif_loop_count(&bytes), Repeat::Finite(5));
+    }
+
+    #[test]
+    fn read_gif_loop_count_defaults_to_play_once_without_extension() {
+        let bytes = b"GIF89a is just a plain still frame with no loop extension".to_vec();
+        assert_eq!(read_gif_loop_count(&bytes), Repeat::Finite(1));
     }
 }